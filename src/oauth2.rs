@@ -1,13 +1,21 @@
-use std::fs::{File, OpenOptions};
-use std::io::{self, BufReader};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
 use log::{info, warn};
+use pbkdf2::pbkdf2_hmac;
 use rand::Rng;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
 
 use crate::oauth2::GrantType::{AuthorizationCode, ClientCredentials, Password};
 use crate::profile::InvalidConfig;
@@ -27,6 +35,11 @@ pub struct OAuth2Config {
     pub redirect: Option<String>,
     pub default_content_type: Option<String>,
     pub default_user_agent: Option<String>,
+    pub default_auth_header_template: Option<String>,
+    pub use_pkce: bool,
+    pub pkce_method: PkceMethod,
+    pub device_authorization_endpoint: Option<String>,
+    pub encrypt_cache: bool,
 }
 
 impl OAuth2Config {
@@ -64,12 +77,104 @@ impl OAuth2Config {
     fn redirect(&self) -> Result<String, AccessTokenError> {
         ok_or(self.redirect.clone(), "redirect")
     }
+
+    fn device_authorization_endpoint(&self) -> Result<String, AccessTokenError> {
+        ok_or(
+            self.device_authorization_endpoint.clone(),
+            "device_authorization_endpoint",
+        )
+    }
 }
 
 fn ok_or<T>(v: Option<T>, fname: &str) -> Result<T, AccessTokenError> {
     v.ok_or_else(|| AccessTokenError::InvalidConfig(fname.to_string()))
 }
 
+// Number of seconds of leeway before the real expiry at which a cached
+// token is already treated as stale, to avoid racing the resource server.
+const TTL_SKEW_SECS: u64 = 30;
+
+// On-disk shape of an encrypted cache file: AES-256-GCM ciphertext plus the
+// salt and nonce it was sealed with, all base64-encoded. The salt is random
+// per file so the same AURL_CACHE_KEY never derives the same cache key twice.
+#[derive(Deserialize, Serialize)]
+struct EncryptedCache {
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+const PBKDF2_SALT_LEN: usize = 16;
+// Deliberately slow: AURL_CACHE_KEY is typically a human-chosen passphrase,
+// and a fast KDF would make offline brute-forcing an exfiltrated cache file cheap.
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+fn cache_secret() -> Option<String> {
+    std::env::var("AURL_CACHE_KEY").ok()
+}
+
+// Derive a 256-bit cache encryption key from AURL_CACHE_KEY and the cache
+// file's salt via PBKDF2-HMAC-SHA256. An OS keyring entry would be a more
+// ergonomic source, but the env var is what's wired up today.
+fn derive_cache_key(secret: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(secret.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+    key
+}
+
+fn encrypt_cache(token: &AccessToken) -> Result<EncryptedCache, AccessTokenError> {
+    let secret = cache_secret().ok_or_else(|| {
+        AccessTokenError::InvalidConfig(
+            "AURL_CACHE_KEY is not set; can not encrypt cache".to_string(),
+        )
+    })?;
+
+    let mut salt = [0u8; PBKDF2_SALT_LEN];
+    rand::thread_rng().fill(&mut salt);
+    let key = derive_cache_key(&secret, &salt);
+    let cipher = Aes256Gcm::new(Key::from_slice(&key));
+
+    let mut nonce_bytes = [0u8; 12];
+    rand::thread_rng().fill(&mut nonce_bytes);
+    let nonce = Nonce::from_slice(&nonce_bytes);
+
+    let plaintext = serde_json::to_vec(token).unwrap();
+    let ciphertext = cipher
+        .encrypt(nonce, plaintext.as_slice())
+        .map_err(|_| AccessTokenError::InvalidCache("can not encrypt cache".to_string()))?;
+
+    Ok(EncryptedCache {
+        salt: base64::encode(salt),
+        nonce: base64::encode(nonce_bytes),
+        ciphertext: base64::encode(ciphertext),
+    })
+}
+
+fn decrypt_cache(encrypted: &EncryptedCache) -> Result<AccessToken, AccessTokenError> {
+    let secret = cache_secret().ok_or_else(|| {
+        AccessTokenError::InvalidCache(
+            "AURL_CACHE_KEY is not set; can not decrypt cache".to_string(),
+        )
+    })?;
+
+    let salt = base64::decode(&encrypted.salt)
+        .map_err(|e| AccessTokenError::InvalidCache(format!("invalid cache salt: {}", e)))?;
+    let key = derive_cache_key(&secret, &salt);
+    let cipher = Aes256Gcm::new(Key::from_slice(&key));
+
+    let nonce_bytes = base64::decode(&encrypted.nonce)
+        .map_err(|e| AccessTokenError::InvalidCache(format!("invalid cache nonce: {}", e)))?;
+    let ciphertext = base64::decode(&encrypted.ciphertext)
+        .map_err(|e| AccessTokenError::InvalidCache(format!("invalid cache ciphertext: {}", e)))?;
+
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(&nonce_bytes), ciphertext.as_slice())
+        .map_err(|_| AccessTokenError::InvalidCache("can not decrypt cache".to_string()))?;
+
+    serde_json::from_slice(&plaintext)
+        .map_err(|e| AccessTokenError::InvalidCache(format!("invalid decrypted cache: {}", e)))
+}
+
 #[derive(Deserialize, Debug, Serialize)]
 pub struct AccessToken {
     pub access_token: String,
@@ -82,37 +187,87 @@ pub struct AccessToken {
 }
 
 impl AccessToken {
-    // Load AccessToken from Cache
+    // Load AccessToken from Cache. Transparently decrypts an encrypted cache
+    // written by save_cache(encrypt: true); a cache that can't be read, parsed
+    // or decrypted is treated the same as no cache at all.
     pub fn load_cache(profile: &str) -> Option<AccessToken> {
-        match File::open(AccessToken::cache_file(profile)) {
-            Ok(f) => {
-                let reader = BufReader::new(f);
-                let token: AccessToken = serde_json::from_reader(reader).unwrap(); // TODO: エラーのときは None で返す
-                Some(token)
-            }
+        let content = match std::fs::read_to_string(AccessToken::cache_file(profile)) {
+            Ok(content) => content,
             Err(_) => {
                 info!("can not find cache file: {}", &profile);
-                None
+                return None;
             }
+        };
+
+        let token = if let Ok(encrypted) = serde_json::from_str::<EncryptedCache>(&content) {
+            match decrypt_cache(&encrypted) {
+                Ok(token) => token,
+                Err(e) => {
+                    warn!("can not decrypt cache file, ignoring it: {:?}", e);
+                    return None;
+                }
+            }
+        } else {
+            match serde_json::from_str::<AccessToken>(&content) {
+                Ok(token) => token,
+                Err(e) => {
+                    warn!("can not parse cache file, ignoring it: {:?}", e);
+                    return None;
+                }
+            }
+        };
+
+        if token.is_expired() {
+            info!("cached token for {} is expired", &profile);
         }
+        Some(token)
     }
 
-    // Save AccessToken in Cache
-    pub fn save_cache(&mut self, profile: &str) -> Result<(), AccessTokenError> {
+    // Remove a (presumably invalid) cached token so the next request fetches a fresh one
+    pub fn remove_cache(profile: &str) {
+        let path = AccessToken::cache_file(profile);
+        if let Err(e) = std::fs::remove_file(&path) {
+            warn!("can not remove cache file: {:?}", e);
+        }
+    }
+
+    // Whether the cached ttl has already passed (with a small skew buffer).
+    // A token with no recorded ttl is treated as expired, since we can't vouch for it.
+    pub fn is_expired(&self) -> bool {
+        match self.ttl {
+            Some(ttl) => {
+                let now = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                now + TTL_SKEW_SECS >= ttl
+            }
+            None => true,
+        }
+    }
+
+    // Save AccessToken in Cache. When `encrypt` is set the cache is written as
+    // an EncryptedCache envelope (AES-256-GCM) instead of plaintext JSON.
+    pub fn save_cache(&mut self, profile: &str, encrypt: bool) -> Result<(), AccessTokenError> {
         // open cache file
         let path = AccessToken::cache_file(profile);
         info!("{:?}", path.as_path());
         let mut cache_file = OpenOptions::new()
             .write(true)
-            .create_new(true)
+            .create(true)
+            .truncate(true)
             .open(path)
             .unwrap();
 
         // Calculate TTL
         self.ttl = Some(AccessToken::calc_ttl(self.expires_in));
 
-        // save json string
-        let str = serde_json::to_string(&self).unwrap();
+        // save json string, optionally encrypted at rest
+        let str = if encrypt {
+            serde_json::to_string(&encrypt_cache(self)?).unwrap()
+        } else {
+            serde_json::to_string(&self).unwrap()
+        };
         info!("Deserialize AccessToken {:?}", str);
 
         match cache_file.write_all(str.as_bytes()) {
@@ -191,9 +346,165 @@ mod test {
             scope: Some("root".to_string()),
             ttl: None,
         };
-        let result = token.save_cache("test").unwrap();
+        let result = token.save_cache("test", false).unwrap();
         assert_eq!((), result);
     }
+
+    fn token_with_ttl(ttl: Option<u64>) -> AccessToken {
+        AccessToken {
+            access_token: "aaaaaa".to_string(),
+            token_type: "bearer".to_string(),
+            expires_in: 3600,
+            id_token: None,
+            refresh_token: None,
+            scope: None,
+            ttl,
+        }
+    }
+
+    fn now_secs() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn test_is_expired_no_ttl() {
+        // a token we can't vouch for is treated as already expired
+        assert!(token_with_ttl(None).is_expired());
+    }
+
+    #[test]
+    fn test_is_expired_well_within_ttl() {
+        let token = token_with_ttl(Some(now_secs() + TTL_SKEW_SECS + 60));
+        assert!(!token.is_expired());
+    }
+
+    #[test]
+    fn test_is_expired_inside_skew_window() {
+        // still has a few seconds left, but less than the skew buffer
+        let token = token_with_ttl(Some(now_secs() + TTL_SKEW_SECS - 1));
+        assert!(token.is_expired());
+    }
+
+    #[test]
+    fn test_is_expired_past_ttl() {
+        let token = token_with_ttl(Some(now_secs().saturating_sub(1)));
+        assert!(token.is_expired());
+    }
+
+    #[test]
+    fn test_encrypted_cache_roundtrip() {
+        std::env::set_var("AURL_CACHE_KEY", "test-encryption-key");
+
+        let mut token = AccessToken {
+            access_token: "aaaaaa".to_string(),
+            token_type: "bearer".to_string(),
+            expires_in: 3600,
+            id_token: None,
+            refresh_token: None,
+            scope: Some("root".to_string()),
+            ttl: None,
+        };
+        token.save_cache("test-encrypted", true).unwrap();
+
+        let loaded = AccessToken::load_cache("test-encrypted").unwrap();
+        assert_eq!(token.access_token, loaded.access_token);
+
+        std::env::remove_var("AURL_CACHE_KEY");
+    }
+
+    #[test]
+    fn test_generate_code_verifier_length_and_charset() {
+        let verifier = generate_code_verifier();
+        assert_eq!(verifier.len(), PKCE_VERIFIER_LEN);
+        assert!(verifier.bytes().all(|b| b.is_ascii_alphanumeric()
+            || b == b'-'
+            || b == b'.'
+            || b == b'_'
+            || b == b'~'));
+    }
+
+    #[test]
+    fn test_generate_code_verifier_is_random() {
+        // not a proof of randomness, but catches an accidentally-constant verifier
+        assert_ne!(generate_code_verifier(), generate_code_verifier());
+    }
+
+    #[test]
+    fn test_code_challenge_s256_rfc7636_vector() {
+        // RFC 7636 Appendix B
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+        let expected = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+        assert_eq!(code_challenge_s256(verifier), expected);
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let params = parse_query("code=abc123&state=xyz");
+        assert_eq!(params.get("code"), Some(&"abc123".to_string()));
+        assert_eq!(params.get("state"), Some(&"xyz".to_string()));
+    }
+
+    #[test]
+    fn test_parse_query_url_encoded_values() {
+        let params = parse_query("state=a+b%2Fc");
+        assert_eq!(params.get("state"), Some(&"a b/c".to_string()));
+    }
+
+    #[test]
+    fn test_url_decode() {
+        assert_eq!(url_decode("hello+world"), "hello world");
+        assert_eq!(url_decode("a%2Fb%3Dc"), "a/b=c");
+        assert_eq!(url_decode("plain"), "plain");
+    }
+
+    #[test]
+    fn test_evaluate_callback_accepts_matching_state() {
+        let request_line = "GET /callback?code=abc123&state=expected HTTP/1.1\r\n";
+        assert_eq!(
+            evaluate_callback(request_line, "expected").unwrap(),
+            "abc123"
+        );
+    }
+
+    #[test]
+    fn test_evaluate_callback_rejects_state_mismatch() {
+        let request_line = "GET /callback?code=abc123&state=attacker HTTP/1.1\r\n";
+        let err = evaluate_callback(request_line, "expected").unwrap_err();
+        match err {
+            AccessTokenError::InvalidConfig(msg) => assert!(msg.contains("CSRF")),
+            other => panic!("expected InvalidConfig, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_evaluate_callback_rejects_missing_code() {
+        let request_line = "GET /callback?state=expected HTTP/1.1\r\n";
+        assert!(evaluate_callback(request_line, "expected").is_err());
+    }
+
+    #[test]
+    fn test_device_poll_action_dispatch() {
+        assert_eq!(
+            device_poll_action("authorization_pending"),
+            DevicePollAction::KeepPolling
+        );
+        assert_eq!(device_poll_action("slow_down"), DevicePollAction::SlowDown);
+        assert_eq!(
+            device_poll_action("expired_token"),
+            DevicePollAction::Expired
+        );
+        assert_eq!(
+            device_poll_action("access_denied"),
+            DevicePollAction::Denied
+        );
+        assert_eq!(
+            device_poll_action("invalid_client"),
+            DevicePollAction::Failed("invalid_client".to_string())
+        );
+    }
 }
 
 #[derive(Debug)]
@@ -201,6 +512,12 @@ pub enum AccessTokenError {
     InvalidCache(String),
     InvalidConfig(String),
     HttpError(reqwest::Error),
+    InvalidResponse(String),
+    // RFC 6749 §5.2 error response from the token/device-authorization endpoint
+    OAuthServer {
+        error: String,
+        description: Option<String>,
+    },
 }
 
 impl From<reqwest::Error> for AccessTokenError {
@@ -209,10 +526,51 @@ impl From<reqwest::Error> for AccessTokenError {
     }
 }
 
+// RFC 6749 §5.2 token error response body
+#[derive(Deserialize, Debug)]
+struct OAuth2ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+    #[allow(dead_code)]
+    error_uri: Option<String>,
+}
+
+// Read a response body once, and on a non-2xx status surface the structured
+// OAuth error instead of a generic decode failure. Shared by every endpoint
+// that speaks RFC 6749 §5.2 errors (token endpoint, device-authorization
+// endpoint, ...), regardless of what shape the success body deserializes into.
+async fn parse_oauth_response<T: serde::de::DeserializeOwned>(
+    res: reqwest::Response,
+) -> Result<T, AccessTokenError> {
+    let status = res.status();
+    let body = res.text().await.map_err(AccessTokenError::HttpError)?;
+
+    if status.is_success() {
+        serde_json::from_str(&body)
+            .map_err(|e| AccessTokenError::InvalidResponse(format!("{}: {}", e, body)))
+    } else {
+        match serde_json::from_str::<OAuth2ErrorResponse>(&body) {
+            Ok(err) => Err(AccessTokenError::OAuthServer {
+                error: err.error,
+                description: err.error_description,
+            }),
+            Err(_) => Err(AccessTokenError::OAuthServer {
+                error: format!("http_{}", status.as_u16()),
+                description: Some(body),
+            }),
+        }
+    }
+}
+
+async fn parse_token_response(res: reqwest::Response) -> Result<AccessToken, AccessTokenError> {
+    parse_oauth_response(res).await
+}
+
 pub enum GrantType {
     Password,
     AuthorizationCode,
     ClientCredentials,
+    DeviceCode,
 }
 
 impl FromStr for GrantType {
@@ -223,17 +581,43 @@ impl FromStr for GrantType {
             "password" => Ok(Password),
             "authorization_code" | "auth" => Ok(AuthorizationCode),
             "client_credentials" | "client" => Ok(ClientCredentials),
+            "device_code" | "device" => Ok(GrantType::DeviceCode),
             _ => Err(InvalidConfig::InvalidGrantType(s.to_string())),
         }
     }
 }
 
+// PKCE (RFC 7636) code_challenge_method
+#[derive(Clone, Copy)]
+pub enum PkceMethod {
+    S256,
+    Plain,
+}
+
+impl FromStr for PkceMethod {
+    type Err = InvalidConfig;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "S256" | "s256" => Ok(PkceMethod::S256),
+            "plain" => Ok(PkceMethod::Plain),
+            _ => Err(InvalidConfig::InvalidPkceMethod(s.to_string())),
+        }
+    }
+}
+
 impl GrantType {
     pub async fn get_access_token(
         &self,
         config: &OAuth2Config,
         http: &Client,
     ) -> Result<AccessToken, AccessTokenError> {
+        // The device flow is a poll loop rather than a single request/response
+        // round trip, so it doesn't fit the shared `.send().await?` tail below.
+        if let GrantType::DeviceCode = self {
+            return Self::poll_device_code(config, http).await;
+        }
+
         let res = match self {
             GrantType::Password => http
                 .post(config.auth_server_token_endpoint()?)
@@ -266,35 +650,76 @@ impl GrantType {
                     ("scope", &config.scopes()?),
                 ]),
             GrantType::AuthorizationCode => {
-                // 1. 認可リクエストのURLを作成
-                let req = http.get(config.auth_server_auth_endpoint()?).query(&[
+                // 1. ループバックアドレスなら先にリスナーをbindしておく。IdPが
+                //    即座にリダイレクトしてくるケース(有効なSSOセッション等)に
+                //    備え、ブラウザを開く前にlistenを完了させておく必要がある。
+                let listener = match loopback_redirect_addr(&config.redirect()?) {
+                    Some(addr) => Some(bind_loopback_listener(addr).await?),
+                    None => None,
+                };
+
+                // 2. 認可リクエストのURLを作成
+                let state = random();
+                let mut req = http.get(config.auth_server_auth_endpoint()?).query(&[
                     ("response_type", "code"),
                     ("client_id", &config.client_id()?),
                     ("scope", &config.scopes()?),
-                    ("state", random().as_str()),
+                    ("state", state.as_str()),
                     ("redirect_uri", config.redirect()?.as_str()),
                 ]);
 
-                // 2. 認可リクエストのURLをブラウザで開く
+                // PKCE: 認可リクエストに code_challenge を添える
+                let code_verifier = if config.use_pkce {
+                    let verifier = generate_code_verifier();
+                    let (challenge, method) = match config.pkce_method {
+                        PkceMethod::S256 => (code_challenge_s256(&verifier), "S256"),
+                        PkceMethod::Plain => (verifier.clone(), "plain"),
+                    };
+                    req = req.query(&[
+                        ("code_challenge", challenge.as_str()),
+                        ("code_challenge_method", method),
+                    ]);
+                    Some(verifier)
+                } else {
+                    None
+                };
+
+                // 3. 認可リクエストのURLをブラウザで開く (ループバックの場合はリスナーのbind後)
                 let req = req.build().unwrap();
                 let url = req.url().as_str();
                 info!("{:?}", url);
 
                 webbrowser::open(url).unwrap();
 
-                // 3. Dummy URL で停止するので URL から認可コードを取得して入力
-                let mut auth_code = String::new();
-
-                loop {
-                    print!("\nEnter authorization code:");
-                    io::stdout().flush().unwrap();
-                    match io::stdin().read_line(&mut auth_code) {
-                        Ok(size) if size > 1 => break,
-                        Err(e) => warn!("{:?}", e),
-                        _ => (),
+                // 4. ループバックリスナーがあれば認可コードを自動取得し、
+                //    そうでなければ従来通り手入力にフォールバックする
+                let auth_code = match listener {
+                    Some(listener) => receive_authorization_code(listener, &state).await?,
+                    None => {
+                        let mut auth_code = String::new();
+                        loop {
+                            print!("\nEnter authorization code:");
+                            io::stdout().flush().unwrap();
+                            match io::stdin().read_line(&mut auth_code) {
+                                Ok(size) if size > 1 => break,
+                                Err(e) => warn!("{:?}", e),
+                                _ => (),
+                            }
+                        }
+                        auth_code.trim().to_string()
                     }
-                }
+                };
+
                 // 4. 認可コードをトークンエンドポイントへ POST. AccessToken を取得
+                let mut form = vec![
+                    ("code", auth_code.as_str()),
+                    ("grant_type", "authorization_code"),
+                    ("redirect_uri", config.redirect()?.as_str()),
+                ];
+                if let Some(verifier) = &code_verifier {
+                    form.push(("code_verifier", verifier.as_str()));
+                }
+
                 http.post(config.auth_server_token_endpoint()?)
                     .basic_auth(config.client_id()?, config.client_secret.clone())
                     .header(
@@ -305,16 +730,169 @@ impl GrantType {
                             .unwrap_or_else(version::name),
                     )
                     .header("content-type", "application/x-www-form-urlencoded")
-                    .form(&[
-                        ("code", auth_code.trim()),
-                        ("grant_type", "authorization_code"),
-                        ("redirect_uri", config.redirect()?.as_str()),
-                    ])
+                    .form(&form)
             }
+            GrantType::DeviceCode => unreachable!("handled above"),
         }
         .send()
         .await?;
-        res.json().await.map_err(AccessTokenError::HttpError)
+        parse_token_response(res).await
+    }
+
+    // Exchange a still-known refresh_token for a new AccessToken instead of
+    // re-running the full (possibly interactive) grant flow.
+    pub async fn refresh_access_token(
+        &self,
+        config: &OAuth2Config,
+        http: &Client,
+        previous: &AccessToken,
+    ) -> Result<AccessToken, AccessTokenError> {
+        let refresh_token = previous
+            .refresh_token
+            .clone()
+            .ok_or_else(|| AccessTokenError::InvalidConfig("refresh_token".to_string()))?;
+
+        let res = http
+            .post(config.auth_server_token_endpoint()?)
+            .basic_auth(config.client_id()?, config.client_secret.clone())
+            .header(
+                USER_AGENT,
+                config
+                    .default_user_agent
+                    .clone()
+                    .unwrap_or_else(version::name),
+            )
+            .form(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token.as_str()),
+            ])
+            .send()
+            .await?;
+
+        let mut token = parse_token_response(res).await?;
+        if token.refresh_token.is_none() {
+            // Some servers omit refresh_token when it hasn't rotated; keep using the old one.
+            token.refresh_token = Some(refresh_token);
+        }
+        Ok(token)
+    }
+
+    // Device Authorization Grant (RFC 8628): obtain a device/user code pair,
+    // show the user where to authenticate, then poll the token endpoint until
+    // they do (or the code expires).
+    async fn poll_device_code(
+        config: &OAuth2Config,
+        http: &Client,
+    ) -> Result<AccessToken, AccessTokenError> {
+        let user_agent = config
+            .default_user_agent
+            .clone()
+            .unwrap_or_else(version::name);
+
+        let res = http
+            .post(config.device_authorization_endpoint()?)
+            .header(USER_AGENT, user_agent.clone())
+            .form(&[
+                ("client_id", &config.client_id()?),
+                ("scope", &config.scopes()?),
+            ])
+            .send()
+            .await?;
+        // Go through the same status/body inspection as the token endpoint so a
+        // rejected device-authorization request (invalid_client, invalid_scope, ...)
+        // surfaces as AccessTokenError::OAuthServer instead of an opaque JSON-decode error.
+        let device_auth: DeviceAuthorization = parse_oauth_response(res).await?;
+
+        println!(
+            "\nTo sign in, visit {} and enter the code: {}",
+            device_auth.verification_uri, device_auth.user_code
+        );
+        if let Some(complete) = &device_auth.verification_uri_complete {
+            println!("Or open this URL directly: {}", complete);
+        }
+
+        let mut interval = Duration::from_secs(device_auth.interval.unwrap_or(5));
+        let deadline = Instant::now() + Duration::from_secs(device_auth.expires_in);
+
+        loop {
+            tokio::time::sleep(interval).await;
+
+            if Instant::now() >= deadline {
+                return Err(AccessTokenError::InvalidConfig(
+                    "device code expired before authorization completed".to_string(),
+                ));
+            }
+
+            let res = http
+                .post(config.auth_server_token_endpoint()?)
+                .basic_auth(config.client_id()?, config.client_secret.clone())
+                .header(USER_AGENT, user_agent.clone())
+                .form(&[
+                    ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+                    ("device_code", device_auth.device_code.as_str()),
+                    ("client_id", &config.client_id()?),
+                ])
+                .send()
+                .await?;
+
+            match parse_token_response(res).await {
+                Ok(token) => return Ok(token),
+                Err(AccessTokenError::OAuthServer { error, .. }) => {
+                    match device_poll_action(&error) {
+                        DevicePollAction::KeepPolling => continue,
+                        DevicePollAction::SlowDown => interval += Duration::from_secs(5),
+                        DevicePollAction::Expired => {
+                            return Err(AccessTokenError::InvalidConfig(
+                                "device code expired".to_string(),
+                            ))
+                        }
+                        DevicePollAction::Denied => {
+                            return Err(AccessTokenError::InvalidConfig(
+                                "user denied the device authorization request".to_string(),
+                            ))
+                        }
+                        DevicePollAction::Failed(other) => {
+                            return Err(AccessTokenError::InvalidConfig(format!(
+                                "device authorization failed: {}",
+                                other
+                            )))
+                        }
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+// What poll_device_code should do next given the token endpoint's RFC 8628 §3.5
+// error code. Kept pure so the branch dispatch is testable without a network loop.
+#[derive(Debug, PartialEq)]
+enum DevicePollAction {
+    KeepPolling,
+    SlowDown,
+    Expired,
+    Denied,
+    Failed(String),
+}
+
+fn device_poll_action(error: &str) -> DevicePollAction {
+    match error {
+        "authorization_pending" => DevicePollAction::KeepPolling,
+        "slow_down" => DevicePollAction::SlowDown,
+        "expired_token" => DevicePollAction::Expired,
+        "access_denied" => DevicePollAction::Denied,
+        other => DevicePollAction::Failed(other.to_string()),
     }
 }
 
@@ -326,3 +904,157 @@ fn random() -> String {
     // TODO: なんかアレなのでどうにかする
     base64::encode(&val.to_be_bytes())
 }
+
+// Generate a PKCE code_verifier (RFC 7636 4.1): 43-128 chars from [A-Za-z0-9-._~]
+const PKCE_VERIFIER_LEN: usize = 64;
+
+fn generate_code_verifier() -> String {
+    const UNRESERVED: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-._~";
+    let mut rng = rand::thread_rng();
+    (0..PKCE_VERIFIER_LEN)
+        .map(|_| UNRESERVED[rng.gen_range(0..UNRESERVED.len())] as char)
+        .collect()
+}
+
+// code_challenge = BASE64URL-NOPAD(SHA256(ASCII(code_verifier)))
+fn code_challenge_s256(verifier: &str) -> String {
+    let digest = Sha256::digest(verifier.as_bytes());
+    base64::encode_config(digest, base64::URL_SAFE_NO_PAD)
+}
+
+// If the redirect URI points at this machine (127.0.0.1/localhost), return the
+// address to bind a one-shot callback listener on instead of asking the user
+// to paste the authorization code by hand.
+fn loopback_redirect_addr(redirect: &str) -> Option<SocketAddr> {
+    let host_port = redirect.splitn(2, "://").nth(1)?.split('/').next()?;
+    let mut it = host_port.splitn(2, ':');
+    let host = it.next()?;
+    let port: u16 = it.next()?.parse().ok()?;
+
+    if host == "127.0.0.1" || host == "localhost" {
+        Some(SocketAddr::from(([127, 0, 0, 1], port)))
+    } else {
+        None
+    }
+}
+
+// How long to wait for the browser to redirect back before giving up, so an
+// abandoned login flow can't hang aurl forever.
+const LOOPBACK_ACCEPT_TIMEOUT: Duration = Duration::from_secs(300);
+
+// Pure decision logic for a received callback request line: extract `code`
+// and reject it (CSRF) unless `state` matches the value we sent. Kept free of
+// any I/O so it can be unit tested without a real socket.
+fn evaluate_callback(request_line: &str, expected_state: &str) -> Result<String, AccessTokenError> {
+    // e.g. "GET /callback?code=xxx&state=yyy HTTP/1.1"
+    let path = request_line.split_whitespace().nth(1).unwrap_or("");
+    let query = path.splitn(2, '?').nth(1).unwrap_or("");
+    let params = parse_query(query);
+
+    let code = params.get("code").cloned();
+    let state_matches = params.get("state").map_or(false, |s| s == expected_state);
+
+    match code {
+        Some(code) if state_matches => Ok(code),
+        Some(_) => Err(AccessTokenError::InvalidConfig(
+            "state mismatch in redirect callback (possible CSRF)".to_string(),
+        )),
+        None => Err(AccessTokenError::InvalidConfig(
+            "redirect callback did not include an authorization code".to_string(),
+        )),
+    }
+}
+
+// Bind the loopback redirect listener. Callers must bind (and keep) this
+// before opening the authorization URL in the browser: if the IdP redirects
+// back immediately (an active SSO session, a non-interactive test IdP, ...),
+// the callback can otherwise reach us before we're listening and get refused.
+async fn bind_loopback_listener(addr: SocketAddr) -> Result<TcpListener, AccessTokenError> {
+    TcpListener::bind(addr).await.map_err(|e| {
+        AccessTokenError::InvalidConfig(format!("can not bind redirect listener: {}", e))
+    })
+}
+
+// Accept a single redirect from the authorization server and hand the
+// request line to evaluate_callback(). Bounded by LOOPBACK_ACCEPT_TIMEOUT so
+// an abandoned browser flow can't hang forever, and built on tokio's async
+// TcpListener so the wait doesn't block the executor thread.
+async fn receive_authorization_code(
+    listener: TcpListener,
+    expected_state: &str,
+) -> Result<String, AccessTokenError> {
+    let (mut stream, _) = tokio::time::timeout(LOOPBACK_ACCEPT_TIMEOUT, listener.accept())
+        .await
+        .map_err(|_| {
+            AccessTokenError::InvalidConfig(
+                "timed out waiting for the authorization redirect".to_string(),
+            )
+        })?
+        .map_err(|e| {
+            AccessTokenError::InvalidConfig(format!("can not accept redirect connection: {}", e))
+        })?;
+
+    let mut request_line = String::new();
+    BufReader::new(&mut stream)
+        .read_line(&mut request_line)
+        .await
+        .map_err(|e| {
+            AccessTokenError::InvalidConfig(format!("can not read redirect request: {}", e))
+        })?;
+
+    let result = evaluate_callback(&request_line, expected_state);
+
+    let (status_line, body) = if result.is_ok() {
+        (
+            "HTTP/1.1 200 OK",
+            "<html><body>Authentication complete. You may close this tab.</body></html>",
+        )
+    } else {
+        (
+            "HTTP/1.1 400 Bad Request",
+            "<html><body>Authentication failed.</body></html>",
+        )
+    };
+    let response = format!(
+        "{}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
+        status_line,
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+
+    result
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|kv| !kv.is_empty())
+        .filter_map(|kv| {
+            let mut it = kv.splitn(2, '=');
+            let key = it.next()?;
+            let value = it.next().unwrap_or("");
+            Some((url_decode(key), url_decode(value)))
+        })
+        .collect()
+}
+
+// Minimal application/x-www-form-urlencoded decoding for ASCII tokens (code/state)
+fn url_decode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '+' => out.push(' '),
+            '%' => match (chars.next(), chars.next()) {
+                (Some(hi), Some(lo)) => match u8::from_str_radix(&format!("{}{}", hi, lo), 16) {
+                    Ok(byte) => out.push(byte as char),
+                    Err(_) => out.push('%'),
+                },
+                _ => out.push('%'),
+            },
+            _ => out.push(c),
+        }
+    }
+    out
+}
@@ -10,6 +10,7 @@ pub enum InvalidConfig {
     MissingFields(String),
     IniFileError(tini::Error),
     InvalidGrantType(String),
+    InvalidPkceMethod(String),
 }
 
 impl Display for InvalidConfig {
@@ -26,6 +27,9 @@ impl Display for InvalidConfig {
                 e
             ),
             InvalidConfig::InvalidGrantType(s) => write!(f, "Invalid GrantType: {}", s),
+            InvalidConfig::InvalidPkceMethod(s) => {
+                write!(f, "Invalid code_challenge_method: {}", s)
+            }
         }
     }
 }
@@ -74,6 +78,12 @@ pub fn read_profiles() -> Result<HashMap<String, OAuth2Config>, InvalidConfig> {
             default_content_type: section.get("default_content_type"),
             default_user_agent: section.get("default_user_agent"),
             default_auth_header_template: section.get("default_auth_header_template"),
+            use_pkce: section.get("use_pkce").unwrap_or(false),
+            pkce_method: section
+                .get("pkce_method")
+                .unwrap_or(crate::oauth2::PkceMethod::S256),
+            device_authorization_endpoint: section.get("device_authorization_endpoint"),
+            encrypt_cache: section.get("encrypt_cache").unwrap_or(false),
         };
         profiles.insert(name.to_string(), profile);
     }
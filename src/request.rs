@@ -16,6 +16,8 @@ pub enum RequestError {
     OAuth(AccessTokenError),
     Http(reqwest::Error),
     InvalidHeader(String),
+    // the resource server responded with a non-2xx status; body is kept for diagnosis
+    ResourceServer { status: StatusCode, body: String },
 }
 
 pub fn same_origin_redirect_policy() -> Policy {
@@ -77,10 +79,31 @@ impl Dispatcher {
             .try_into()
             .map_err(|e| RequestError::InvalidHeader(format!("{:?}", e)))?;
 
+        // A 401 drops the cache and fetches one fresh token, once. If the
+        // resource server still rejects the freshly-fetched token, the
+        // problem isn't a stale cache (wrong scope, revoked client, ...), so
+        // surface it instead of looping forever.
+        let mut retried_after_unauthorized = false;
+
         loop {
             // test load cache from profile
             let mut token = match AccessToken::load_cache(&opts.profile) {
-                Some(t) => t,
+                Some(t) if !t.is_expired() => t,
+                Some(stale) => match oauth2
+                    .grant_type
+                    .refresh_access_token(oauth2, &self.client, &stale)
+                    .await
+                {
+                    Ok(refreshed) => refreshed,
+                    Err(e) => {
+                        warn!("can not refresh token, falling back to new grant. {:?}", e);
+                        oauth2
+                            .grant_type
+                            .get_access_token(oauth2, &self.client)
+                            .await
+                            .map_err(RequestError::OAuth)?
+                    }
+                },
                 None => oauth2
                     .grant_type
                     .get_access_token(oauth2, &self.client)
@@ -91,7 +114,7 @@ impl Dispatcher {
 
             // save cache with AccessToken
             token
-                .save_cache(&opts.profile)
+                .save_cache(&opts.profile, oauth2.encrypt_cache)
                 .unwrap_or_else(|err| warn!("can not save cache. {:?}", err));
             let req = self
                 .client
@@ -122,10 +145,18 @@ impl Dispatcher {
             let res = req.send().await;
             debug!("{:?}", res);
             match res {
-                Ok(ok) => return Ok(ok),
-                Err(e) if e.status().map_or(false, |s| s == StatusCode::UNAUTHORIZED) => {
+                Ok(ok)
+                    if ok.status() == StatusCode::UNAUTHORIZED && !retried_after_unauthorized =>
+                {
+                    retried_after_unauthorized = true;
                     AccessToken::remove_cache(&opts.profile)
                 }
+                Ok(ok) if !ok.status().is_success() => {
+                    let status = ok.status();
+                    let body = ok.text().await.unwrap_or_default();
+                    return Err(RequestError::ResourceServer { status, body });
+                }
+                Ok(ok) => return Ok(ok),
                 Err(e) => return Err(RequestError::Http(e)),
             }
         }